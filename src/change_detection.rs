@@ -0,0 +1,127 @@
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+
+/// Maps bean-relative paths to the module that owns them, so `sync --changed`
+/// can figure out which modules a set of changed files actually touches.
+/// Ownership is registered as a path prefix (e.g. `Kernel` owns
+/// `bean_relative_dev_path`); a changed path is resolved to the deepest
+/// prefix that contains it.
+#[derive(Default)]
+pub(crate) struct ModuleTrie {
+    root: TrieNode,
+}
+
+#[derive(Default)]
+struct TrieNode {
+    children: HashMap<std::ffi::OsString, TrieNode>,
+    module_id: Option<String>,
+}
+
+impl ModuleTrie {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `prefix` (relative to the bean) as owned by `module_id`.
+    pub(crate) fn insert(&mut self, prefix: &Path, module_id: &str) {
+        let mut node = &mut self.root;
+        for component in prefix.components() {
+            node = node
+                .children
+                .entry(component.as_os_str().to_os_string())
+                .or_default();
+        }
+        node.module_id = Some(module_id.to_string());
+    }
+
+    /// Walks `path` down the trie and returns the id of the deepest owning
+    /// module, if any prefix of `path` was registered.
+    pub(crate) fn owner(&self, path: &Path) -> Option<&str> {
+        let mut node = &self.root;
+        let mut owner = node.module_id.as_deref();
+
+        for component in path.components() {
+            match node.children.get(component.as_os_str()) {
+                Some(next) => {
+                    node = next;
+                    if let Some(id) = node.module_id.as_deref() {
+                        owner = Some(id);
+                    }
+                }
+                None => break,
+            }
+        }
+
+        owner
+    }
+
+    /// Resolves each changed path to its owning module, returning the set of
+    /// module ids actually touched.
+    pub(crate) fn affected_modules<'a>(
+        &self,
+        changed_paths: impl IntoIterator<Item = &'a PathBuf>,
+    ) -> BTreeSet<String> {
+        changed_paths
+            .into_iter()
+            .filter_map(|path| self.owner(path))
+            .map(ToString::to_string)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn owner_resolves_to_registered_prefix() {
+        let mut trie = ModuleTrie::new();
+        trie.insert(Path::new("kernel/dev"), "kernel");
+
+        assert_eq!(
+            trie.owner(Path::new("kernel/dev/arch/x86/Kconfig")),
+            Some("kernel")
+        );
+    }
+
+    #[test]
+    fn owner_is_none_outside_any_prefix() {
+        let mut trie = ModuleTrie::new();
+        trie.insert(Path::new("kernel/dev"), "kernel");
+
+        assert_eq!(trie.owner(Path::new("mkosi/mkosi.conf")), None);
+    }
+
+    #[test]
+    fn owner_picks_the_deepest_overlapping_prefix() {
+        let mut trie = ModuleTrie::new();
+        trie.insert(Path::new("kernel"), "kernel");
+        trie.insert(Path::new("kernel/dev"), "kernel-dev");
+
+        assert_eq!(
+            trie.owner(Path::new("kernel/dev/Makefile")),
+            Some("kernel-dev")
+        );
+        assert_eq!(trie.owner(Path::new("kernel/README")), Some("kernel"));
+    }
+
+    #[test]
+    fn affected_modules_dedupes_and_ignores_unowned_paths() {
+        let mut trie = ModuleTrie::new();
+        trie.insert(Path::new("kernel/dev"), "kernel");
+        trie.insert(Path::new("mkosi"), "mkosi_kernel");
+
+        let changed = vec![
+            PathBuf::from("kernel/dev/a.c"),
+            PathBuf::from("kernel/dev/b.c"),
+            PathBuf::from("mkosi/mkosi.conf"),
+            PathBuf::from("unowned/file.txt"),
+        ];
+
+        let affected = trie.affected_modules(&changed);
+        assert_eq!(
+            affected,
+            BTreeSet::from(["kernel".to_string(), "mkosi_kernel".to_string()])
+        );
+    }
+}
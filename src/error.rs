@@ -0,0 +1,88 @@
+use std::fmt;
+
+use serde::Serialize;
+
+/// Broad category of failure, so callers can react to (or the CLI can map)
+/// a failure without string-matching a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ErrorClass {
+    Git,
+    Io,
+    TomlDeserialize,
+    TomlSerialize,
+    Command,
+    Utf8,
+    Config,
+    DirtyWorktree,
+    UnloadedModule,
+}
+
+/// The crate's error type. Carries enough to both print a useful message and
+/// pick a stable process exit code.
+#[derive(Debug)]
+pub(crate) struct BeanError {
+    pub(crate) class: ErrorClass,
+    pub(crate) message: String,
+}
+
+impl BeanError {
+    pub(crate) fn new(class: ErrorClass, message: impl Into<String>) -> Self {
+        BeanError {
+            class,
+            message: message.into(),
+        }
+    }
+
+    pub(crate) fn exit_code(&self) -> i32 {
+        match self.class {
+            ErrorClass::Git => 10,
+            ErrorClass::Io => 11,
+            ErrorClass::TomlDeserialize => 12,
+            ErrorClass::TomlSerialize => 18,
+            ErrorClass::Command => 13,
+            ErrorClass::Utf8 => 14,
+            ErrorClass::Config => 15,
+            ErrorClass::DirtyWorktree => 16,
+            ErrorClass::UnloadedModule => 17,
+        }
+    }
+}
+
+impl fmt::Display for BeanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for BeanError {}
+
+impl From<std::io::Error> for BeanError {
+    fn from(err: std::io::Error) -> Self {
+        BeanError::new(ErrorClass::Io, err.to_string())
+    }
+}
+
+impl From<std::str::Utf8Error> for BeanError {
+    fn from(err: std::str::Utf8Error) -> Self {
+        BeanError::new(ErrorClass::Utf8, err.to_string())
+    }
+}
+
+impl From<git2::Error> for BeanError {
+    fn from(err: git2::Error) -> Self {
+        BeanError::new(ErrorClass::Git, err.to_string())
+    }
+}
+
+impl From<toml::de::Error> for BeanError {
+    fn from(err: toml::de::Error) -> Self {
+        BeanError::new(ErrorClass::TomlDeserialize, err.to_string())
+    }
+}
+
+impl From<toml::ser::Error> for BeanError {
+    fn from(err: toml::ser::Error) -> Self {
+        BeanError::new(ErrorClass::TomlSerialize, err.to_string())
+    }
+}
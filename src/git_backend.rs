@@ -0,0 +1,652 @@
+use std::path::{Path, PathBuf};
+
+use crate::error::{BeanError, ErrorClass};
+use crate::util::Expectations;
+
+/// A parsed working-tree status report, counting entries by the kind of
+/// change rather than just a clean/dirty bool.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct WorktreeStatus {
+    pub(crate) staged: usize,
+    pub(crate) modified: usize,
+    pub(crate) deleted: usize,
+    pub(crate) renamed: usize,
+    pub(crate) untracked: usize,
+    pub(crate) conflicted: usize,
+    pub(crate) ahead: usize,
+    pub(crate) behind: usize,
+}
+
+impl WorktreeStatus {
+    /// A tree is clean iff every change count is zero. Ahead/behind counts
+    /// don't factor in here since they don't reflect uncommitted work.
+    pub(crate) fn is_clean(&self) -> bool {
+        self.staged == 0
+            && self.modified == 0
+            && self.deleted == 0
+            && self.renamed == 0
+            && self.untracked == 0
+            && self.conflicted == 0
+    }
+
+    /// A short, human-readable reason like "3 modified, 1 untracked", or
+    /// "clean" when there's nothing to report.
+    pub(crate) fn summary(&self) -> String {
+        let parts: Vec<String> = [
+            (self.staged, "staged"),
+            (self.modified, "modified"),
+            (self.deleted, "deleted"),
+            (self.renamed, "renamed"),
+            (self.untracked, "untracked"),
+            (self.conflicted, "conflicted"),
+        ]
+        .into_iter()
+        .filter(|(count, _)| *count > 0)
+        .map(|(count, label)| format!("{} {}", count, label))
+        .collect();
+
+        if parts.is_empty() {
+            "clean".to_string()
+        } else {
+            parts.join(", ")
+        }
+    }
+}
+
+/// Parses `git status --porcelain=v2 --branch` output. Lines are prefixed by
+/// type: `1`/`2` carry a two-char XY code (X = index state, Y = worktree
+/// state), `u` lines are unmerged/conflicted, and `?` lines are untracked.
+fn parse_porcelain_v2(output: &str) -> WorktreeStatus {
+    let mut status = WorktreeStatus::default();
+
+    for line in output.lines() {
+        match line.as_bytes().first() {
+            Some(b'1') | Some(b'2') => {
+                let xy = &line[2..4];
+                let (x, y) = (xy.as_bytes()[0], xy.as_bytes()[1]);
+
+                if x != b'.' {
+                    status.staged += 1;
+                }
+                match y {
+                    b'M' => status.modified += 1,
+                    b'D' => status.deleted += 1,
+                    _ => (),
+                }
+                if x == b'R' || y == b'R' {
+                    status.renamed += 1;
+                }
+            }
+            Some(b'u') => status.conflicted += 1,
+            Some(b'?') => status.untracked += 1,
+            Some(b'#') => {
+                if let Some(ab) = line.strip_prefix("# branch.ab ") {
+                    for token in ab.split_whitespace() {
+                        if let Some(n) = token.strip_prefix('+') {
+                            status.ahead = n.parse().unwrap_or(0);
+                        } else if let Some(n) = token.strip_prefix('-') {
+                            status.behind = n.parse().unwrap_or(0);
+                        }
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    status
+}
+
+/// Abstracts the git operations `Module` implementations need, so tests can
+/// inject a fake that records calls without touching a real repo, and so
+/// backends (the `git` CLI or the in-process `libgit2` one) can be swapped
+/// without touching call sites.
+pub(crate) trait GitBackend {
+    /// `allow_existing` tolerates exit 128 (e.g. "already exists"), for the
+    /// clean/detached worktree a sync can reuse; a fresh dev-branch worktree
+    /// should leave this `false` so a real failure isn't swallowed.
+    fn worktree_add(
+        &self,
+        repo: &Path,
+        path: &Path,
+        new_branch: Option<&str>,
+        detach: bool,
+        allow_existing: bool,
+    ) -> Result<(), BeanError>;
+
+    fn worktree_remove(&self, repo: &Path, path: &Path) -> Result<(), BeanError>;
+
+    fn switch_detached(&self, path: &Path, branch: &str) -> Result<(), BeanError>;
+
+    fn current_branch(&self, path: &Path) -> Result<String, BeanError>;
+
+    fn head_hash(&self, path: &Path) -> Result<String, BeanError>;
+
+    fn status(&self, path: &Path) -> Result<WorktreeStatus, BeanError>;
+
+    /// Lists paths (relative to `path`) that differ between `base` and the
+    /// current `HEAD`.
+    fn changed_paths(&self, path: &Path, base: &str) -> Result<Vec<PathBuf>, BeanError>;
+
+    /// Writes a numbered patch series for `range` (`<base>..<head>`) into
+    /// `output_dir`, optionally prefixed with a cover letter.
+    fn format_patch(
+        &self,
+        path: &Path,
+        range: &str,
+        output_dir: &Path,
+        cover_letter: bool,
+    ) -> Result<(), BeanError>;
+
+    /// Writes a self-contained bundle of `range` (`<base>..<head>`) to
+    /// `output_path`.
+    fn bundle(&self, path: &Path, range: &str, output_path: &Path) -> Result<(), BeanError>;
+}
+
+/// Reclassifies a generic `Command` failure as `Git`, so a failing git
+/// invocation is distinguishable from a non-git subprocess (e.g. `mkosi`).
+fn as_git_error(err: BeanError) -> BeanError {
+    match err.class {
+        ErrorClass::Command => BeanError::new(ErrorClass::Git, err.message),
+        _ => err,
+    }
+}
+
+/// Shells out to the `git` binary on `PATH`.
+pub(crate) struct CliGit;
+
+impl GitBackend for CliGit {
+    fn worktree_add(
+        &self,
+        repo: &Path,
+        path: &Path,
+        new_branch: Option<&str>,
+        detach: bool,
+        allow_existing: bool,
+    ) -> Result<(), BeanError> {
+        let mut command = std::process::Command::new("git");
+        command.current_dir(repo).arg("worktree").arg("add").arg(path);
+
+        if let Some(branch) = new_branch {
+            command.arg("-b").arg(branch);
+        }
+        if detach {
+            command.arg("-d");
+        }
+
+        if allow_existing {
+            command.expect(&[0, 128])
+        } else {
+            command.expect_success()
+        }
+        .map_err(as_git_error)
+    }
+
+    fn worktree_remove(&self, repo: &Path, path: &Path) -> Result<(), BeanError> {
+        std::process::Command::new("git")
+            .current_dir(repo)
+            .arg("worktree")
+            .arg("remove")
+            .arg(path)
+            .expect_success()
+            .map_err(as_git_error)
+    }
+
+    fn switch_detached(&self, path: &Path, branch: &str) -> Result<(), BeanError> {
+        std::process::Command::new("git")
+            .current_dir(path)
+            .arg("switch")
+            .arg(branch)
+            .arg("--detach")
+            .expect_success()
+            .map_err(as_git_error)
+    }
+
+    fn current_branch(&self, path: &Path) -> Result<String, BeanError> {
+        let output = std::process::Command::new("git")
+            .current_dir(path)
+            .stdout(std::process::Stdio::piped())
+            .arg("branch")
+            .arg("--show-current")
+            .output_checked()
+            .map_err(as_git_error)?;
+
+        Ok(String::from_utf8_lossy(&output.stdout).trim_end().to_string())
+    }
+
+    fn head_hash(&self, path: &Path) -> Result<String, BeanError> {
+        let output = std::process::Command::new("git")
+            .current_dir(path)
+            .stdout(std::process::Stdio::piped())
+            .arg("log")
+            .arg("-1")
+            .arg("--pretty=%H")
+            .output_checked()
+            .map_err(as_git_error)?;
+
+        Ok(String::from_utf8_lossy(&output.stdout).trim_end().to_string())
+    }
+
+    fn status(&self, path: &Path) -> Result<WorktreeStatus, BeanError> {
+        let output = std::process::Command::new("git")
+            .current_dir(path)
+            .stdout(std::process::Stdio::piped())
+            .arg("status")
+            .arg("--porcelain=v2")
+            .arg("--branch")
+            .output_checked()
+            .map_err(as_git_error)?;
+
+        Ok(parse_porcelain_v2(&String::from_utf8_lossy(&output.stdout)))
+    }
+
+    fn changed_paths(&self, path: &Path, base: &str) -> Result<Vec<PathBuf>, BeanError> {
+        let output = std::process::Command::new("git")
+            .current_dir(path)
+            .stdout(std::process::Stdio::piped())
+            .arg("diff")
+            .arg("--name-only")
+            .arg(format!("{base}..HEAD"))
+            .output_checked()
+            .map_err(as_git_error)?;
+
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(PathBuf::from)
+            .collect())
+    }
+
+    fn format_patch(
+        &self,
+        path: &Path,
+        range: &str,
+        output_dir: &Path,
+        cover_letter: bool,
+    ) -> Result<(), BeanError> {
+        std::fs::create_dir_all(output_dir)?;
+
+        let mut command = std::process::Command::new("git");
+        command
+            .current_dir(path)
+            .arg("format-patch")
+            .arg(range)
+            .arg("-o")
+            .arg(output_dir);
+
+        if cover_letter {
+            command.arg("--cover-letter");
+        }
+
+        command.expect_success().map_err(as_git_error)
+    }
+
+    fn bundle(&self, path: &Path, range: &str, output_path: &Path) -> Result<(), BeanError> {
+        std::process::Command::new("git")
+            .current_dir(path)
+            .arg("bundle")
+            .arg("create")
+            .arg(output_path)
+            .arg(range)
+            .expect_success()
+            .map_err(as_git_error)
+    }
+}
+
+/// In-process implementation backed by `libgit2`, avoiding a dependency on the
+/// `git` binary being present on `PATH` and avoiding parsing human-readable
+/// CLI output. The one exception is [`GitBackend::bundle`], which still runs
+/// the `git` binary.
+pub(crate) struct LibGit;
+
+impl GitBackend for LibGit {
+    fn worktree_add(
+        &self,
+        repo: &Path,
+        path: &Path,
+        new_branch: Option<&str>,
+        detach: bool,
+        allow_existing: bool,
+    ) -> Result<(), BeanError> {
+        let repo = git2::Repository::open(repo)?;
+        let name = crate::util::bean_name_from_(path)?
+            .to_str()
+            .ok_or_else(|| BeanError::new(ErrorClass::Utf8, "non-utf8 worktree name"))?;
+
+        let mut opts = git2::WorktreeAddOptions::new();
+
+        let reference;
+        if let Some(branch) = new_branch {
+            let head = repo.head()?.peel_to_commit()?;
+            let branch = repo.branch(branch, &head, false)?;
+            reference = branch.into_reference();
+            opts.reference(Some(&reference));
+        } else if detach {
+            opts.reference(None);
+        }
+
+        match repo.worktree(name, path, Some(&opts)) {
+            Ok(_) => Ok(()),
+            Err(err) if allow_existing && err.code() == git2::ErrorCode::Exists => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn worktree_remove(&self, repo: &Path, path: &Path) -> Result<(), BeanError> {
+        let repo = git2::Repository::open(repo)?;
+        let name = crate::util::bean_name_from_(path)?
+            .to_str()
+            .ok_or_else(|| BeanError::new(ErrorClass::Utf8, "non-utf8 worktree name"))?;
+
+        repo.find_worktree(name)?.prune(None)?;
+
+        Ok(())
+    }
+
+    fn switch_detached(&self, path: &Path, branch: &str) -> Result<(), BeanError> {
+        let repo = git2::Repository::open(path)?;
+        let oid = repo.revparse_single(branch)?.peel_to_commit()?.id();
+
+        repo.set_head_detached(oid)?;
+        repo.checkout_head(Some(git2::build::CheckoutBuilder::new().force()))?;
+
+        Ok(())
+    }
+
+    fn current_branch(&self, path: &Path) -> Result<String, BeanError> {
+        let repo = git2::Repository::open(path)?;
+        let head = repo.head()?;
+
+        Ok(head.shorthand().unwrap_or_default().to_string())
+    }
+
+    fn head_hash(&self, path: &Path) -> Result<String, BeanError> {
+        let repo = git2::Repository::open(path)?;
+        let hash = repo.head()?.peel_to_commit()?.id();
+
+        Ok(hash.to_string())
+    }
+
+    fn status(&self, path: &Path) -> Result<WorktreeStatus, BeanError> {
+        let repo = git2::Repository::open(path)?;
+
+        let mut opts = git2::StatusOptions::new();
+        opts.include_untracked(true);
+
+        let mut status = WorktreeStatus::default();
+
+        for entry in repo.statuses(Some(&mut opts))?.iter() {
+            let flags = entry.status();
+
+            if flags.intersects(
+                git2::Status::INDEX_NEW
+                    | git2::Status::INDEX_MODIFIED
+                    | git2::Status::INDEX_DELETED
+                    | git2::Status::INDEX_RENAMED
+                    | git2::Status::INDEX_TYPECHANGE,
+            ) {
+                status.staged += 1;
+            }
+            if flags.intersects(git2::Status::WT_MODIFIED) {
+                status.modified += 1;
+            }
+            if flags.intersects(git2::Status::WT_DELETED) {
+                status.deleted += 1;
+            }
+            if flags.intersects(git2::Status::INDEX_RENAMED | git2::Status::WT_RENAMED) {
+                status.renamed += 1;
+            }
+            if flags.intersects(git2::Status::WT_NEW) {
+                status.untracked += 1;
+            }
+            if flags.intersects(git2::Status::CONFLICTED) {
+                status.conflicted += 1;
+            }
+        }
+
+        if let Ok(head) = repo.head() {
+            if let (Some(name), Some(local_oid)) = (head.name(), head.target()) {
+                if let Some((ahead, behind)) = repo
+                    .branch_upstream_name(name)
+                    .ok()
+                    .and_then(|upstream| upstream.as_str().map(ToString::to_string))
+                    .and_then(|upstream| repo.find_reference(&upstream).ok())
+                    .and_then(|upstream_ref| upstream_ref.target())
+                    .and_then(|upstream_oid| repo.graph_ahead_behind(local_oid, upstream_oid).ok())
+                {
+                    status.ahead = ahead;
+                    status.behind = behind;
+                }
+            }
+        }
+
+        Ok(status)
+    }
+
+    fn changed_paths(&self, path: &Path, base: &str) -> Result<Vec<PathBuf>, BeanError> {
+        let repo = git2::Repository::open(path)?;
+
+        let base_tree = repo.revparse_single(base)?.peel_to_tree()?;
+        let head_tree = repo.head()?.peel_to_tree()?;
+
+        let diff = repo.diff_tree_to_tree(Some(&base_tree), Some(&head_tree), None)?;
+
+        Ok(diff
+            .deltas()
+            .filter_map(|delta| delta.new_file().path().map(Path::to_path_buf))
+            .collect())
+    }
+
+    fn format_patch(
+        &self,
+        path: &Path,
+        range: &str,
+        output_dir: &Path,
+        cover_letter: bool,
+    ) -> Result<(), BeanError> {
+        let repo = git2::Repository::open(path)?;
+        std::fs::create_dir_all(output_dir)?;
+
+        let (base, head) = range.split_once("..").ok_or_else(|| {
+            BeanError::new(
+                ErrorClass::Config,
+                "range must be formatted as <base>..<head>",
+            )
+        })?;
+
+        let base_oid = repo.revparse_single(base)?.peel_to_commit()?.id();
+        let head_oid = repo.revparse_single(head)?.peel_to_commit()?.id();
+
+        let mut revwalk = repo.revwalk()?;
+        revwalk.push(head_oid)?;
+        revwalk.hide(base_oid)?;
+        revwalk.set_sorting(git2::Sort::REVERSE | git2::Sort::TOPOLOGICAL)?;
+
+        let commits = revwalk.collect::<Result<Vec<_>, _>>()?;
+        let width = commits.len().to_string().len().max(4);
+
+        if cover_letter {
+            std::fs::write(
+                output_dir.join(format!("{:0width$}-cover-letter.patch", 0, width = width)),
+                "",
+            )?;
+        }
+
+        for (index, oid) in commits.into_iter().enumerate() {
+            let commit = repo.find_commit(oid)?;
+            let tree = commit.tree()?;
+            let parent_tree = commit.parent(0).ok().map(|p| p.tree()).transpose()?;
+
+            let diff = repo.diff_tree_to_tree(parent_tree.as_ref(), Some(&tree), None)?;
+
+            let mut patch = Vec::new();
+            diff.print(git2::DiffFormat::Patch, |_, _, line| {
+                patch.extend_from_slice(line.content());
+                true
+            })?;
+
+            let subject = commit.summary().unwrap_or("patch").replace(' ', "-");
+            let patch_path =
+                output_dir.join(format!("{:0width$}-{}.patch", index + 1, subject, width = width));
+            std::fs::write(patch_path, patch)?;
+        }
+
+        Ok(())
+    }
+
+    /// `git2` has no API for writing the `git bundle` wire format, so this
+    /// one operation shells out to the `git` binary even on the libgit2
+    /// backend — a documented, narrow exception rather than a stub error.
+    fn bundle(&self, path: &Path, range: &str, output_path: &Path) -> Result<(), BeanError> {
+        std::process::Command::new("git")
+            .current_dir(path)
+            .arg("bundle")
+            .arg("create")
+            .arg(output_path)
+            .arg(range)
+            .expect_success()
+            .map_err(as_git_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_clean_tree() {
+        let status = parse_porcelain_v2("# branch.oid abc123\n# branch.head main\n");
+
+        assert!(status.is_clean());
+        assert_eq!(status.ahead, 0);
+        assert_eq!(status.behind, 0);
+    }
+
+    #[test]
+    fn parses_staged_modified_and_untracked() {
+        let output = "\
+# branch.head main
+1 M. N... 100644 100644 100644 aaa bbb src/lib.rs
+1 .M N... 100644 100644 100644 aaa bbb src/main.rs
+? scratch.txt
+";
+        let status = parse_porcelain_v2(output);
+
+        assert_eq!(status.staged, 1);
+        assert_eq!(status.modified, 1);
+        assert_eq!(status.untracked, 1);
+        assert!(!status.is_clean());
+    }
+
+    #[test]
+    fn parses_renames_from_either_side() {
+        let output = "\
+2 R. N... 100644 100644 100644 aaa bbb R100 src/new.rs\tsrc/old.rs
+";
+        let status = parse_porcelain_v2(output);
+
+        assert_eq!(status.renamed, 1);
+        assert_eq!(status.staged, 1);
+    }
+
+    #[test]
+    fn parses_conflicted_entries() {
+        let output = "u UU N... 100644 100644 100644 100644 aaa bbb ccc src/conflict.rs\n";
+        let status = parse_porcelain_v2(output);
+
+        assert_eq!(status.conflicted, 1);
+        assert!(!status.is_clean());
+    }
+
+    #[test]
+    fn parses_ahead_behind_without_affecting_clean() {
+        let output = "# branch.ab +2 -3\n";
+        let status = parse_porcelain_v2(output);
+
+        assert_eq!(status.ahead, 2);
+        assert_eq!(status.behind, 3);
+        assert!(status.is_clean());
+    }
+
+    #[test]
+    fn ignores_unrecognized_lines() {
+        let status = parse_porcelain_v2("! garbage that git never emits\n");
+        assert!(status.is_clean());
+    }
+}
+
+/// A [`GitBackend`] that records the calls made against it instead of
+/// touching a real repo, for `Module` impls to test against.
+#[cfg(test)]
+pub(crate) mod fake {
+    use std::cell::RefCell;
+
+    use super::*;
+
+    #[derive(Default)]
+    pub(crate) struct FakeGitBackend {
+        pub(crate) calls: RefCell<Vec<String>>,
+        pub(crate) current_branch: String,
+        pub(crate) head_hash: String,
+        pub(crate) status: WorktreeStatus,
+    }
+
+    impl GitBackend for FakeGitBackend {
+        fn worktree_add(
+            &self,
+            _repo: &Path,
+            _path: &Path,
+            _new_branch: Option<&str>,
+            _detach: bool,
+            _allow_existing: bool,
+        ) -> Result<(), BeanError> {
+            self.calls.borrow_mut().push("worktree_add".to_string());
+            Ok(())
+        }
+
+        fn worktree_remove(&self, _repo: &Path, _path: &Path) -> Result<(), BeanError> {
+            self.calls.borrow_mut().push("worktree_remove".to_string());
+            Ok(())
+        }
+
+        fn switch_detached(&self, _path: &Path, _branch: &str) -> Result<(), BeanError> {
+            self.calls.borrow_mut().push("switch_detached".to_string());
+            Ok(())
+        }
+
+        fn current_branch(&self, _path: &Path) -> Result<String, BeanError> {
+            self.calls.borrow_mut().push("current_branch".to_string());
+            Ok(self.current_branch.clone())
+        }
+
+        fn head_hash(&self, _path: &Path) -> Result<String, BeanError> {
+            self.calls.borrow_mut().push("head_hash".to_string());
+            Ok(self.head_hash.clone())
+        }
+
+        fn status(&self, _path: &Path) -> Result<WorktreeStatus, BeanError> {
+            self.calls.borrow_mut().push("status".to_string());
+            Ok(self.status)
+        }
+
+        fn changed_paths(&self, _path: &Path, _base: &str) -> Result<Vec<PathBuf>, BeanError> {
+            self.calls.borrow_mut().push("changed_paths".to_string());
+            Ok(Vec::new())
+        }
+
+        fn format_patch(
+            &self,
+            _path: &Path,
+            _range: &str,
+            _output_dir: &Path,
+            _cover_letter: bool,
+        ) -> Result<(), BeanError> {
+            self.calls.borrow_mut().push("format_patch".to_string());
+            Ok(())
+        }
+
+        fn bundle(&self, _path: &Path, _range: &str, _output_path: &Path) -> Result<(), BeanError> {
+            self.calls.borrow_mut().push("bundle".to_string());
+            Ok(())
+        }
+    }
+}
@@ -1,10 +1,10 @@
-use std::error::Error;
-
 use serde::{Deserialize, Serialize};
 
 use crate::{
+    error::{BeanError, ErrorClass},
+    git_backend::GitBackend,
     module::Module,
-    util::{Expectations, bean_name_from_, git_branch, git_hash, git_status},
+    util::bean_name_from_,
 };
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -34,10 +34,30 @@ impl Kernel {
             module_status: KernelStatus::Unloaded,
         }
     }
+
+    /// The kernel's dev worktree, where a developer's unsubmitted commits
+    /// live.
+    pub(crate) fn dev_path(&self, bean_path: &std::path::Path) -> std::path::PathBuf {
+        bean_path.join(&self.bean_relative_dev_path)
+    }
+
+    /// The clean base hash the dev worktree last synced against, i.e. the
+    /// start of the range a reviewable patch series or bundle should cover.
+    pub(crate) fn base_hash(&self) -> Option<&str> {
+        match &self.module_status {
+            KernelStatus::Unloaded => None,
+            KernelStatus::Loaded { hash, .. } => Some(hash),
+        }
+    }
 }
 
 impl Module<'_> for Kernel {
-    fn load(&mut self, bean_path: &std::path::Path) -> Result<(), Box<dyn Error>> {
+    fn load(
+        &mut self,
+        bean_path: &std::path::Path,
+        git: &dyn GitBackend,
+        _kernel_dev_path: Option<&std::path::Path>,
+    ) -> Result<(), BeanError> {
         match self.module_status {
             KernelStatus::Loaded { branch: _, hash: _ } => return Ok(()),
             KernelStatus::Unloaded => (),
@@ -45,72 +65,65 @@ impl Module<'_> for Kernel {
 
         let module_path = bean_path.join(&self.bean_relative_dev_path);
 
-        std::process::Command::new("git")
-            .current_dir(&self.source_path)
-            .arg("worktree")
-            .arg("add")
-            .arg(&module_path)
-            .arg("-b")
-            .arg(bean_name_from_(bean_path)?)
-            .status()?
-            .expect_success()?;
-
-        std::process::Command::new("git")
-            .current_dir(&self.source_path)
-            .arg("worktree")
-            .arg("add")
-            .arg(&self.clean_path)
-            .arg("-d")
-            .status()?
-            .expect(&[0, 128])?;
+        git.worktree_add(
+            &self.source_path,
+            &module_path,
+            Some(
+                bean_name_from_(bean_path)?
+                    .to_str()
+                    .ok_or_else(|| BeanError::new(ErrorClass::Utf8, "non-utf8 bean name"))?,
+            ),
+            false,
+            false,
+        )?;
+
+        git.worktree_add(&self.source_path, &self.clean_path, None, true, true)?;
 
         self.module_status = KernelStatus::Loaded {
-            branch: git_branch(&module_path)?,
-            hash: git_hash(&module_path)?,
+            branch: git.current_branch(&module_path)?,
+            hash: git.head_hash(&module_path)?,
         };
 
         Ok(())
     }
 
-    fn sync(&mut self, bean_path: &std::path::Path) -> Result<(), Box<dyn Error>> {
+    fn sync(&mut self, bean_path: &std::path::Path, git: &dyn GitBackend) -> Result<(), BeanError> {
         match self.module_status {
             KernelStatus::Unloaded => {
-                return Err(Box::new(std::io::Error::new(
-                    std::io::ErrorKind::PermissionDenied,
+                return Err(BeanError::new(
+                    ErrorClass::UnloadedModule,
                     "cannot sync an unloaded module",
-                )));
+                ));
             }
             KernelStatus::Loaded { branch: _, hash: _ } => (),
         }
 
         let module_path = bean_path.join(&self.bean_relative_dev_path);
 
-        if !git_status(&module_path)? {
-            return Err(Box::new(std::io::Error::new(
-                std::io::ErrorKind::PermissionDenied,
-                "working directory not clean... make sure to commit all changes",
-            )));
+        let status = git.status(&module_path)?;
+        if !status.is_clean() {
+            return Err(BeanError::new(
+                ErrorClass::DirtyWorktree,
+                format!(
+                    "working directory not clean ({})... make sure to commit all changes",
+                    status.summary()
+                ),
+            ));
         }
 
-        let branch = git_branch(&module_path)?;
+        let branch = git.current_branch(&module_path)?;
 
-        std::process::Command::new("git")
-            .current_dir(&self.clean_path)
-            .arg("switch")
-            .arg(&branch)
-            .arg("--detach")
-            .status()?
-            .expect_success()?;
+        git.switch_detached(&self.clean_path, &branch)?;
 
         self.module_status = KernelStatus::Loaded {
             branch,
-            hash: git_hash(&module_path)?,
+            hash: git.head_hash(&module_path)?,
         };
 
         Ok(())
     }
 
-    fn unload(&mut self, bean_path: &std::path::Path) -> Result<(), Box<dyn Error>> {
+    fn unload(&mut self, bean_path: &std::path::Path, git: &dyn GitBackend) -> Result<(), BeanError> {
         match self.module_status {
             KernelStatus::Unloaded => return Ok(()),
             KernelStatus::Loaded { branch: _, hash: _ } => (),
@@ -120,14 +133,116 @@ impl Module<'_> for Kernel {
 
         self.module_status = KernelStatus::Unloaded;
 
-        std::process::Command::new("git")
-            .current_dir(&self.source_path)
-            .arg("worktree")
-            .arg("remove")
-            .arg(&module_path)
-            .status()?
-            .expect_success()?;
+        git.worktree_remove(&self.source_path, &module_path)?;
 
         Ok(())
     }
+
+    fn owned_prefix(&self) -> std::path::PathBuf {
+        self.bean_relative_dev_path.clone()
+    }
+
+    fn changed_paths(
+        &self,
+        bean_path: &std::path::Path,
+        git: &dyn GitBackend,
+    ) -> Result<Vec<std::path::PathBuf>, BeanError> {
+        let base = match &self.module_status {
+            KernelStatus::Unloaded => return Ok(Vec::new()),
+            KernelStatus::Loaded { hash, .. } => hash,
+        };
+
+        let module_path = bean_path.join(&self.bean_relative_dev_path);
+
+        Ok(git
+            .changed_paths(&module_path, base)?
+            .into_iter()
+            .map(|path| self.bean_relative_dev_path.join(path))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git_backend::fake::FakeGitBackend;
+
+    fn unloaded() -> Kernel {
+        Kernel::setup(
+            std::path::Path::new("/src/kernel"),
+            std::path::Path::new("/bean/.clean/kernel"),
+            std::path::Path::new("kernel/dev"),
+        )
+    }
+
+    #[test]
+    fn load_adds_worktrees_and_records_branch_and_hash() {
+        let mut kernel = unloaded();
+        let git = FakeGitBackend {
+            current_branch: "my-feature".to_string(),
+            head_hash: "deadbeef".to_string(),
+            ..Default::default()
+        };
+
+        kernel.load(std::path::Path::new("/bean"), &git, None).unwrap();
+
+        assert_eq!(kernel.base_hash(), Some("deadbeef"));
+        assert_eq!(
+            *git.calls.borrow(),
+            vec!["worktree_add", "worktree_add", "current_branch", "head_hash"]
+        );
+    }
+
+    #[test]
+    fn sync_on_unloaded_module_errors() {
+        let mut kernel = unloaded();
+        let git = FakeGitBackend::default();
+
+        let err = kernel
+            .sync(std::path::Path::new("/bean"), &git)
+            .unwrap_err();
+
+        assert_eq!(err.class, ErrorClass::UnloadedModule);
+    }
+
+    #[test]
+    fn sync_on_dirty_worktree_errors() {
+        let mut kernel = unloaded();
+        let git = FakeGitBackend {
+            current_branch: "main".to_string(),
+            head_hash: "aaa".to_string(),
+            ..Default::default()
+        };
+        kernel.load(std::path::Path::new("/bean"), &git, None).unwrap();
+
+        let dirty_git = FakeGitBackend {
+            status: crate::git_backend::WorktreeStatus {
+                modified: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let err = kernel
+            .sync(std::path::Path::new("/bean"), &dirty_git)
+            .unwrap_err();
+
+        assert_eq!(err.class, ErrorClass::DirtyWorktree);
+    }
+
+    #[test]
+    fn unload_removes_the_dev_worktree() {
+        let mut kernel = unloaded();
+        let git = FakeGitBackend {
+            current_branch: "main".to_string(),
+            head_hash: "aaa".to_string(),
+            ..Default::default()
+        };
+        kernel.load(std::path::Path::new("/bean"), &git, None).unwrap();
+
+        kernel.unload(std::path::Path::new("/bean"), &git).unwrap();
+
+        assert!(matches!(kernel.module_status, KernelStatus::Unloaded));
+        assert_eq!(git.calls.borrow().last().unwrap(), "worktree_remove");
+    }
 }
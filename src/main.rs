@@ -1,11 +1,18 @@
 use std::{env::current_dir, io::Read};
 
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 use serde::{Deserialize, Serialize};
 
+use crate::change_detection::ModuleTrie;
+use crate::error::{BeanError, ErrorClass};
+use crate::git_backend::GitBackend;
 use crate::kernel::Kernel;
 use crate::mkosi_kernel::MkosiKernel;
+use crate::module::Module;
 
+mod change_detection;
+mod error;
+mod git_backend;
 mod kernel;
 mod mkosi_kernel;
 mod module;
@@ -19,10 +26,58 @@ struct BeanConfig {
     mkosi_kernel: MkosiKernel,
 }
 
+impl BeanConfig {
+    fn module_trie(&self) -> ModuleTrie {
+        let mut trie = ModuleTrie::new();
+        trie.insert(&self.kernel.owned_prefix(), "kernel");
+        trie.insert(&self.mkosi_kernel.owned_prefix(), "mkosi_kernel");
+        trie
+    }
+
+    /// Returns the ids of the modules whose owned paths contain at least one
+    /// changed file, for `sync --changed`.
+    fn changed_modules(
+        &self,
+        bean_path: &std::path::Path,
+        git: &dyn GitBackend,
+    ) -> Result<std::collections::BTreeSet<String>, BeanError> {
+        let trie = self.module_trie();
+
+        let mut changed_paths = Vec::new();
+        changed_paths.extend(self.kernel.changed_paths(bean_path, git)?);
+        changed_paths.extend(self.mkosi_kernel.changed_paths(bean_path, git)?);
+
+        Ok(trie.affected_modules(&changed_paths))
+    }
+}
+
 #[derive(Parser)]
 struct CLI {
     #[clap(subcommand)]
     command: Command,
+
+    /// Print errors as a structured JSON report instead of plain text.
+    #[clap(long, global = true)]
+    json: bool,
+
+    /// Which git implementation to run commands against.
+    #[clap(long, global = true, value_enum, default_value_t = GitBackendKind::Cli)]
+    backend: GitBackendKind,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum GitBackendKind {
+    /// Shells out to the `git` binary on `PATH`.
+    Cli,
+    /// Runs in-process against `libgit2`.
+    Lib,
+}
+
+fn select_git_backend(kind: GitBackendKind) -> Box<dyn GitBackend> {
+    match kind {
+        GitBackendKind::Cli => Box::new(git_backend::CliGit),
+        GitBackendKind::Lib => Box::new(git_backend::LibGit),
+    }
 }
 
 #[derive(Subcommand)]
@@ -41,6 +96,11 @@ enum Command {
 
         #[clap(short, long)]
         all: bool,
+
+        /// Sync only the modules whose owned paths have changed since their
+        /// last sync.
+        #[clap(long)]
+        changed: bool,
     },
     Mkosi {
         #[clap(default_value=get_current_bean())]
@@ -49,6 +109,31 @@ enum Command {
         #[clap(last=true)]
         mkosi_args: Vec<String>,
     },
+    Patch {
+        #[clap(default_value=get_current_bean())]
+        bean: std::path::PathBuf,
+
+        /// Override the base of the range to export, instead of the kernel
+        /// module's clean base hash.
+        #[clap(long)]
+        since: Option<String>,
+
+        #[clap(subcommand)]
+        form: PatchForm,
+    },
+}
+
+#[derive(Subcommand)]
+enum PatchForm {
+    /// Export a numbered patch series via `git format-patch`.
+    Series {
+        output_dir: std::path::PathBuf,
+
+        #[clap(long)]
+        cover_letter: bool,
+    },
+    /// Export a self-contained `git bundle`.
+    Bundle { output_path: std::path::PathBuf },
 }
 
 fn get_current_bean() -> Option<&'static str> {
@@ -64,7 +149,155 @@ fn get_current_bean() -> Option<&'static str> {
     None
 }
 
+fn read_bean_config(bean: &std::path::Path) -> Result<BeanConfig, BeanError> {
+    let contents = std::fs::read_to_string(bean.join(BEAN_CONFIG_FILE))?;
+    Ok(toml::from_str(&contents)?)
+}
+
+fn write_bean_config(bean: &std::path::Path, config: &BeanConfig) -> Result<(), BeanError> {
+    std::fs::write(bean.join(BEAN_CONFIG_FILE), toml::to_string_pretty(config)?)?;
+    Ok(())
+}
+
+fn run_mkosi(
+    bean: &std::path::Path,
+    mkosi_args: &[String],
+    backend: GitBackendKind,
+) -> Result<(), BeanError> {
+    let mut config = read_bean_config(bean)?;
+    let git = select_git_backend(backend);
+
+    let kernel_dev_path = config.kernel.dev_path(bean);
+    let kernel_hash = git.head_hash(&kernel_dev_path)?;
+
+    config.mkosi_kernel.sync_with_kernel(
+        bean,
+        &kernel_dev_path,
+        &kernel_hash,
+        mkosi_args,
+        &mkosi_kernel::ShellMkosi,
+    )?;
+
+    write_bean_config(bean, &config)
+}
+
+fn run_patch(
+    bean: &std::path::Path,
+    since: Option<String>,
+    form: PatchForm,
+    backend: GitBackendKind,
+) -> Result<(), BeanError> {
+    let config = read_bean_config(bean)?;
+    let git = select_git_backend(backend);
+
+    let dev_path = config.kernel.dev_path(bean);
+    let base = match since {
+        Some(base) => base,
+        None => config
+            .kernel
+            .base_hash()
+            .ok_or_else(|| BeanError::new(ErrorClass::UnloadedModule, "kernel module is not loaded"))?
+            .to_string(),
+    };
+    let range = format!("{base}..HEAD");
+
+    match form {
+        PatchForm::Series {
+            output_dir,
+            cover_letter,
+        } => git.format_patch(&dev_path, &range, &output_dir, cover_letter),
+        PatchForm::Bundle { output_path } => git.bundle(&dev_path, &range, &output_path),
+    }
+}
+
+fn run_sync(
+    bean: &std::path::Path,
+    modules: &[String],
+    all: bool,
+    changed: bool,
+    backend: GitBackendKind,
+) -> Result<(), BeanError> {
+    let mut config = read_bean_config(bean)?;
+    let git = select_git_backend(backend);
+
+    let targets: std::collections::BTreeSet<String> = if all {
+        ["kernel", "mkosi_kernel"].into_iter().map(String::from).collect()
+    } else if changed {
+        config.changed_modules(bean, git.as_ref())?
+    } else if !modules.is_empty() {
+        modules.iter().cloned().collect()
+    } else {
+        return Err(BeanError::new(
+            ErrorClass::Config,
+            "sync needs a module list, --all, or --changed",
+        ));
+    };
+
+    for target in &targets {
+        match target.as_str() {
+            "kernel" => config.kernel.sync(bean, git.as_ref())?,
+            "mkosi_kernel" => config.mkosi_kernel.sync(bean, git.as_ref())?,
+            other => {
+                return Err(BeanError::new(
+                    ErrorClass::Config,
+                    format!("unknown module {other}"),
+                ));
+            }
+        }
+    }
+
+    write_bean_config(bean, &config)
+}
+
+#[derive(Serialize)]
+struct ErrorReport<'a> {
+    class: ErrorClass,
+    message: &'a str,
+}
+
+fn report_and_exit(err: BeanError, json: bool) -> ! {
+    if json {
+        let report = ErrorReport {
+            class: err.class,
+            message: &err.message,
+        };
+        eprintln!(
+            "{}",
+            serde_json::to_string(&report).unwrap_or_else(|_| err.message.clone())
+        );
+    } else {
+        eprintln!("{err}");
+    }
+
+    std::process::exit(err.exit_code());
+}
+
 fn main() {
-    CLI::parse();
-    println!("Hello world");
+    let cli = CLI::parse();
+
+    match cli.command {
+        Command::Mkosi { bean, mkosi_args } => {
+            if let Err(err) = run_mkosi(&bean, &mkosi_args, cli.backend) {
+                report_and_exit(err, cli.json);
+            }
+        }
+        Command::Patch { bean, since, form } => {
+            if let Err(err) = run_patch(&bean, since, form, cli.backend) {
+                report_and_exit(err, cli.json);
+            }
+        }
+        Command::Sync {
+            bean,
+            modules,
+            all,
+            changed,
+        } => {
+            if let Err(err) = run_sync(&bean, &modules, all, changed, cli.backend) {
+                report_and_exit(err, cli.json);
+            }
+        }
+        Command::New { .. } => {
+            println!("Hello world");
+        }
+    }
 }
@@ -1,9 +1,29 @@
-use std::error::Error;
-
 use serde::{Deserialize, Serialize};
 
+use crate::error::BeanError;
+use crate::git_backend::GitBackend;
+
 pub(crate) trait Module<'de>: Serialize + Deserialize<'de> {
-    fn load(&mut self, bean_path: &std::path::Path) -> Result<(), Box<dyn Error>>;
-    fn sync(&mut self, bean_path: &std::path::Path) -> Result<(), Box<dyn Error>>;
-    fn unload(&mut self, bean_path: &std::path::Path) -> Result<(), Box<dyn Error>>;
+    /// `kernel_dev_path` is the Kernel module's dev worktree, which modules
+    /// whose config is generated from the kernel's location (e.g.
+    /// `MkosiKernel`) need at load time; modules that don't care ignore it.
+    fn load(
+        &mut self,
+        bean_path: &std::path::Path,
+        git: &dyn GitBackend,
+        kernel_dev_path: Option<&std::path::Path>,
+    ) -> Result<(), BeanError>;
+    fn sync(&mut self, bean_path: &std::path::Path, git: &dyn GitBackend) -> Result<(), BeanError>;
+    fn unload(&mut self, bean_path: &std::path::Path, git: &dyn GitBackend) -> Result<(), BeanError>;
+
+    /// The bean-relative path prefix this module owns, for change detection.
+    fn owned_prefix(&self) -> std::path::PathBuf;
+
+    /// Bean-relative paths this module's worktree has changed since it was
+    /// last synced. Empty if the module isn't loaded or tracks no base.
+    fn changed_paths(
+        &self,
+        bean_path: &std::path::Path,
+        git: &dyn GitBackend,
+    ) -> Result<Vec<std::path::PathBuf>, BeanError>;
 }
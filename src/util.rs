@@ -1,85 +1,68 @@
-use std::error::Error;
+use crate::error::{BeanError, ErrorClass};
 
 pub(crate) trait Expectations {
-    fn expect_success(&self) -> Result<(), Box<dyn Error>>;
-    fn expect(&self, code: &[i32]) -> Result<(), Box<dyn Error>>;
+    fn expect_success(&mut self) -> Result<(), BeanError>;
+    fn expect(&mut self, codes: &[i32]) -> Result<(), BeanError>;
+    fn output_checked(&mut self) -> Result<std::process::Output, BeanError>;
 }
 
-impl Expectations for std::process::ExitStatus {
-    fn expect_success(&self) -> Result<(), Box<dyn Error>> {
-        if self.success() {
-            Ok(())
-        } else {
-            Err(Box::new(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "expected success",
-            )))
-        }
+impl Expectations for std::process::Command {
+    fn expect_success(&mut self) -> Result<(), BeanError> {
+        self.expect(&[0])
     }
 
-    fn expect(&self, code: &[i32]) -> Result<(), Box<dyn Error>> {
-        let actual = self.code().ok_or(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "unable to get exit code for process",
-        ))?;
+    fn expect(&mut self, codes: &[i32]) -> Result<(), BeanError> {
+        let argv = self.argv();
+        let actual = self.status()?.code();
 
-        for &c in code {
-            if actual == c {
-                return Ok(());
-            }
+        if actual.is_some_and(|code| codes.contains(&code)) {
+            return Ok(());
         }
 
-        Err(Box::new(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "unexpected error code",
-        )))
+        Err(BeanError::new(
+            ErrorClass::Command,
+            format!(
+                "`{}` exited with {:?}, expected one of {:?}",
+                argv, actual, codes
+            ),
+        ))
     }
-}
-
-pub(crate) fn bean_name_from_(
-    bean_path: &std::path::Path,
-) -> Result<&std::ffi::OsStr, Box<dyn Error>> {
-    bean_path.file_name().ok_or(Box::new(std::io::Error::new(
-        std::io::ErrorKind::NotFound,
-        format!("unable to get bean name from bean_path, {:?}", bean_path),
-    )))
-}
 
-pub(crate) fn git_branch(path: &std::path::Path) -> Result<String, Box<dyn Error>> {
-    let output = std::process::Command::new("git")
-        .current_dir(path)
-        .stdout(std::process::Stdio::piped())
-        .arg("branch")
-        .arg("--show-current")
-        .output()?;
+    fn output_checked(&mut self) -> Result<std::process::Output, BeanError> {
+        let argv = self.argv();
+        let output = self.output()?;
 
-    output.status.expect_success()?;
+        if output.status.success() {
+            return Ok(output);
+        }
 
-    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+        Err(BeanError::new(
+            ErrorClass::Command,
+            format!("`{}` exited with {:?}", argv, output.status.code()),
+        ))
+    }
 }
 
-pub(crate) fn git_hash(path: &std::path::Path) -> Result<String, Box<dyn Error>> {
-    let output = std::process::Command::new("git")
-        .current_dir(path)
-        .stdout(std::process::Stdio::piped())
-        .arg("log")
-        .arg("--pretty=%H")
-        .output()?;
-
-    output.status.expect_success()?;
-
-    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+trait Argv {
+    fn argv(&self) -> String;
 }
 
-pub(crate) fn git_status(path: &std::path::Path) -> Result<bool, Box<dyn Error>> {
-    let output = std::process::Command::new("git")
-        .current_dir(path)
-        .stdout(std::process::Stdio::piped())
-        .arg("log")
-        .arg("--pretty=%H")
-        .output()?;
-
-    output.status.expect_success()?;
+impl Argv for std::process::Command {
+    fn argv(&self) -> String {
+        std::iter::once(self.get_program().to_string_lossy().into_owned())
+            .chain(self.get_args().map(|arg| arg.to_string_lossy().into_owned()))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
 
-    Ok(output.stdout.len() > 0)
+pub(crate) fn bean_name_from_(
+    bean_path: &std::path::Path,
+) -> Result<&std::ffi::OsStr, BeanError> {
+    bean_path.file_name().ok_or_else(|| {
+        BeanError::new(
+            ErrorClass::Config,
+            format!("unable to get bean name from bean_path, {:?}", bean_path),
+        )
+    })
 }